@@ -1,6 +1,11 @@
 #![allow(unsafe_code)]
 
-use std::sync::{Arc, RwLock};
+use std::sync::Arc;
+#[cfg(not(feature = "thread-safe"))]
+use std::sync::RwLock;
+
+#[cfg(feature = "thread-safe")]
+use super::striped_lock::StripedRwLock;
 
 #[link(wasm_import_module = "ic0")]
 extern "C" {
@@ -52,6 +57,12 @@ pub(crate) trait Memory: Sync + Clone {
     /// Copies the data referred to by src and replaces the
     /// corresponding segment starting at offset in the stable memory.
     fn write(&self, offset: u64, src: &[u8]);
+
+    /// Releases `pages` many trailing pages back to the backing store, if
+    /// the implementation supports shrinking at all. IC stable memory can
+    /// only ever grow, so this is a no-op by default; test/off-chain
+    /// backends that can actually free memory should override it.
+    fn shrink(&self, _pages: u64) {}
 }
 
 const WASM_PAGE_SIZE: u64 = 65536;
@@ -59,9 +70,21 @@ const WASM_PAGE_SIZE: u64 = 65536;
 const MAX_PAGES: u64 = i64::MAX as u64 / WASM_PAGE_SIZE;
 
 /// A `Memory` that is based on a vector.
+///
+/// Behind the `thread-safe` feature this is backed by a [`StripedRwLock`]
+/// instead of a plain `RwLock`, so concurrent reads of the buffer usually
+/// land on different shards. `Connection::lock` does not use this lock; its
+/// read-to-write upgrade goes through a separate `StripedRwLock<()>` owned by
+/// `PagesVfs`.
+#[cfg(not(feature = "thread-safe"))]
 #[derive(Clone, Debug)]
 pub(crate) struct VectorMemory(Arc<RwLock<Vec<u8>>>);
 
+#[cfg(feature = "thread-safe")]
+#[derive(Clone, Debug)]
+pub(crate) struct VectorMemory(Arc<StripedRwLock<Vec<u8>>>);
+
+#[cfg(not(feature = "thread-safe"))]
 impl Default for VectorMemory {
     fn default() -> Self {
         let buffer: Vec<u8> = vec![0; 20];
@@ -69,6 +92,15 @@ impl Default for VectorMemory {
     }
 }
 
+#[cfg(feature = "thread-safe")]
+impl Default for VectorMemory {
+    fn default() -> Self {
+        let buffer: Vec<u8> = vec![0; 20];
+        Self(Arc::new(StripedRwLock::new(buffer)))
+    }
+}
+
+#[cfg(not(feature = "thread-safe"))]
 impl Memory for VectorMemory {
     fn size(&self) -> u64 {
         self.0.read().unwrap().len() as u64 / WASM_PAGE_SIZE
@@ -113,4 +145,65 @@ impl Memory for VectorMemory {
         }
         self.0.write().unwrap()[offset as usize..n as usize].copy_from_slice(src);
     }
+
+    fn shrink(&self, pages: u64) {
+        let mut buffer = self.0.write().unwrap();
+        let new_len = buffer
+            .len()
+            .saturating_sub((pages * WASM_PAGE_SIZE) as usize);
+        buffer.truncate(new_len);
+    }
+}
+
+#[cfg(feature = "thread-safe")]
+impl Memory for VectorMemory {
+    fn size(&self) -> u64 {
+        self.0.read().len() as u64 / WASM_PAGE_SIZE
+    }
+
+    fn grow(&self, pages: u64) -> i64 {
+        let size = self.size();
+        match size.checked_add(pages) {
+            Some(n) => {
+                if n > MAX_PAGES {
+                    return -1;
+                }
+                self.0.write().resize((n * WASM_PAGE_SIZE) as usize, 0);
+                size as i64
+            }
+            None => -1,
+        }
+    }
+
+    fn read(&self, offset: u64, dst: &mut [u8]) {
+        let n = offset
+            .checked_add(dst.len() as u64)
+            .expect("read: out of bounds");
+
+        let buffer = self.0.read();
+        if n as usize > buffer.len() {
+            panic!("read: out of bounds");
+        }
+        dst.copy_from_slice(&buffer[offset as usize..n as usize]);
+    }
+
+    fn write(&self, offset: u64, src: &[u8]) {
+        let n = offset
+            .checked_add(src.len() as u64)
+            .expect("write: out of bounds");
+
+        let mut buffer = self.0.write();
+        if n as usize > buffer.len() {
+            panic!("write: out of bounds");
+        }
+        buffer[offset as usize..n as usize].copy_from_slice(src);
+    }
+
+    fn shrink(&self, pages: u64) {
+        let mut buffer = self.0.write();
+        let new_len = buffer
+            .len()
+            .saturating_sub((pages * WASM_PAGE_SIZE) as usize);
+        buffer.truncate(new_len);
+    }
 }
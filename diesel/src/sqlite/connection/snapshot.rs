@@ -0,0 +1,161 @@
+//! Content-addressed snapshots of a database's pages.
+//!
+//! [`PageMerkleTree`] keeps a Merkle tree over the content id (BLAKE3 hash)
+//! of every page, addressed directly by page index like an array-mapped
+//! trie. Only the path from a touched leaf to the root is rehashed, so
+//! committing `n` pages costs `O(n * tree_height)`.
+//!
+//! A [`Snapshot`] is a cheap, owned copy of the leaf hashes at a point in
+//! time; diffing a snapshot against the live tree
+//! ([`PageMerkleTree::changed_pages`]) tells you exactly which pages changed,
+//! enough to ship a delta instead of the whole database on canister
+//! upgrade/backup. Two snapshots are not compared with each other directly.
+
+use std::collections::BTreeSet;
+
+/// A BLAKE3 content id.
+pub(crate) type ContentId = [u8; 32];
+
+fn hash_page(page: &[u8]) -> ContentId {
+    *blake3::hash(page).as_bytes()
+}
+
+fn hash_node(left: &ContentId, right: &ContentId) -> ContentId {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(left);
+    hasher.update(right);
+    *hasher.finalize().as_bytes()
+}
+
+/// An owned copy of a [`PageMerkleTree`]'s leaf hashes, taken at a point in
+/// time. Cheap to keep around; diffing it against the live tree is the basis
+/// for `export_changed_pages`.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Snapshot {
+    leaves: Vec<ContentId>,
+}
+
+impl Snapshot {
+    /// Content id of `page_index` as of this snapshot, or the all-zero id
+    /// if the page didn't exist yet.
+    fn leaf(&self, page_index: usize) -> ContentId {
+        self.leaves.get(page_index).copied().unwrap_or([0u8; 32])
+    }
+}
+
+/// Incrementally-maintained Merkle tree over a database's pages.
+#[derive(Debug, Default)]
+pub(crate) struct PageMerkleTree {
+    /// `levels[0]` holds the per-page leaf hashes (padded with the all-zero
+    /// id up to a power of two); each subsequent level hashes pairs from
+    /// the one below; `levels.last()` is always a single-element root
+    /// level.
+    levels: Vec<Vec<ContentId>>,
+    /// Node indices, per level, that have changed since the last `root()`.
+    dirty: Vec<BTreeSet<usize>>,
+}
+
+impl PageMerkleTree {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records that `page_index` now has the given content, rehashing its
+    /// leaf immediately and marking that leaf dirty. Its ancestors are only
+    /// marked and rehashed lazily, in `root()`.
+    pub(crate) fn touch(&mut self, page_index: u64, page: &[u8]) {
+        let page_index = page_index as usize;
+        self.ensure_capacity(page_index + 1);
+
+        let hash = hash_page(page);
+        if self.levels[0][page_index] == hash {
+            return;
+        }
+        self.levels[0][page_index] = hash;
+        self.dirty[0].insert(page_index);
+    }
+
+    /// The current Merkle root over every page. Only the nodes on the path
+    /// from a touched leaf to the root are rehashed; untouched subtrees are
+    /// left alone.
+    pub(crate) fn root(&mut self) -> ContentId {
+        if self.levels.is_empty() {
+            self.ensure_capacity(1);
+        }
+
+        for level in 0..self.levels.len() - 1 {
+            let dirty = std::mem::take(&mut self.dirty[level]);
+            for index in dirty {
+                let sibling_index = index ^ 1;
+                let sibling = self.levels[level]
+                    .get(sibling_index)
+                    .copied()
+                    .unwrap_or([0u8; 32]);
+                let (left, right) = if index % 2 == 0 {
+                    (self.levels[level][index], sibling)
+                } else {
+                    (sibling, self.levels[level][index])
+                };
+
+                let parent_index = index / 2;
+                let parent = hash_node(&left, &right);
+                if self.levels[level + 1][parent_index] != parent {
+                    self.levels[level + 1][parent_index] = parent;
+                    self.dirty[level + 1].insert(parent_index);
+                }
+            }
+        }
+
+        self.levels[self.levels.len() - 1][0]
+    }
+
+    /// Takes an owned copy of the current leaf hashes.
+    pub(crate) fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            leaves: self.levels.first().cloned().unwrap_or_default(),
+        }
+    }
+
+    /// Page indices whose content id differs between `since` and the
+    /// current tree (including pages that didn't exist in `since` yet).
+    pub(crate) fn changed_pages(&self, since: &Snapshot) -> Vec<u64> {
+        let leaves = self.levels.first().map(Vec::as_slice).unwrap_or(&[]);
+        leaves
+            .iter()
+            .enumerate()
+            .filter(|(index, hash)| **hash != since.leaf(*index))
+            .map(|(index, _)| index as u64)
+            .collect()
+    }
+
+    /// Grows the leaf level (and rebuilds the tree shape above it) so it can
+    /// address at least `pages` pages. Not incremental, since padding to the
+    /// next power of two changes every level's size; only runs when the
+    /// database grows past its previous page-count high-water mark.
+    fn ensure_capacity(&mut self, pages: usize) {
+        let current_pages = self.levels.first().map(Vec::len).unwrap_or(0);
+        if pages <= current_pages {
+            return;
+        }
+
+        let leaf_count = pages.next_power_of_two().max(1);
+        let mut leaves = self.levels.first().cloned().unwrap_or_default();
+        leaves.resize(leaf_count, [0u8; 32]);
+
+        let mut levels = vec![leaves];
+        while levels.last().unwrap().len() > 1 {
+            let below = levels.last().unwrap();
+            let mut level = Vec::with_capacity(below.len() / 2);
+            for pair in below.chunks(2) {
+                level.push(hash_node(&pair[0], &pair[1]));
+            }
+            levels.push(level);
+        }
+
+        self.dirty = levels
+            .iter()
+            .map(|level| (0..level.len()).collect())
+            .collect();
+        self.levels = levels;
+    }
+}
@@ -0,0 +1,269 @@
+//! A reader-writer lock striped across several independent shards, so
+//! concurrent readers usually land on different shards instead of all
+//! contending on one cache line. A writer still excludes every reader -- it
+//! just has to acquire every shard instead of one.
+//!
+//! Unlike a plain `RwLock`, a held [`StripedReadGuard`] can attempt to
+//! upgrade itself to a [`StripedWriteGuard`] in place via
+//! [`StripedReadGuard::try_upgrade`] without ever releasing its own shard,
+//! failing (rather than blocking) if that isn't possible right away --
+//! which is what SQLite expects when moving a lock from `Shared` to
+//! `Reserved`/`Exclusive`.
+
+#![allow(unsafe_code)]
+
+use std::cell::UnsafeCell;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+const SHARDS: usize = 8;
+const WRITE_LOCKED: usize = usize::MAX;
+
+/// A value guarded by `SHARDS` independent reader counters. `0` means free,
+/// `WRITE_LOCKED` means exclusively held, anything else is a reader count.
+pub(crate) struct StripedRwLock<T> {
+    shards: [AtomicUsize; SHARDS],
+    data: UnsafeCell<T>,
+    next_reader: AtomicUsize,
+}
+
+impl<T> std::fmt::Debug for StripedRwLock<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let shards: Vec<usize> = self
+            .shards
+            .iter()
+            .map(|s| s.load(Ordering::Relaxed))
+            .collect();
+        f.debug_struct("StripedRwLock")
+            .field("shards", &shards)
+            .finish_non_exhaustive()
+    }
+}
+
+// SAFETY: access to `data` is only ever handed out through a guard that has
+// proven (via the shard counters) it holds the matching kind of access,
+// exactly as `std::sync::RwLock` does internally.
+unsafe impl<T: Send> Send for StripedRwLock<T> {}
+unsafe impl<T: Send> Sync for StripedRwLock<T> {}
+
+impl<T> StripedRwLock<T> {
+    pub(crate) fn new(value: T) -> Self {
+        Self {
+            shards: [0; SHARDS].map(AtomicUsize::new),
+            data: UnsafeCell::new(value),
+            next_reader: AtomicUsize::new(0),
+        }
+    }
+
+    /// Acquires a read lock on one shard, chosen round-robin so repeated
+    /// callers spread out across the stripe rather than piling onto shard 0.
+    pub(crate) fn read(self: &Arc<Self>) -> StripedReadGuard<T> {
+        let shard = self.next_reader.fetch_add(1, Ordering::Relaxed) % SHARDS;
+        loop {
+            let current = self.shards[shard].load(Ordering::Acquire);
+            if current == WRITE_LOCKED {
+                std::thread::yield_now();
+                continue;
+            }
+            if self.shards[shard]
+                .compare_exchange_weak(current, current + 1, Ordering::AcqRel, Ordering::Relaxed)
+                .is_ok()
+            {
+                return StripedReadGuard {
+                    lock: self.clone(),
+                    shard,
+                };
+            }
+        }
+    }
+
+    /// Acquires every shard for exclusive access, in a fixed order so two
+    /// concurrent writers can never deadlock against each other.
+    pub(crate) fn write(self: &Arc<Self>) -> StripedWriteGuard<T> {
+        for shard in 0..SHARDS {
+            loop {
+                if self.shards[shard]
+                    .compare_exchange(0, WRITE_LOCKED, Ordering::AcqRel, Ordering::Relaxed)
+                    .is_ok()
+                {
+                    break;
+                }
+                std::thread::yield_now();
+            }
+        }
+        StripedWriteGuard { lock: self.clone() }
+    }
+}
+
+impl<T: Default> Default for StripedRwLock<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+/// A read guard over one shard of a [`StripedRwLock`].
+pub(crate) struct StripedReadGuard<T> {
+    lock: Arc<StripedRwLock<T>>,
+    shard: usize,
+}
+
+impl<T> std::fmt::Debug for StripedReadGuard<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StripedReadGuard")
+            .field("shard", &self.shard)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T> Deref for StripedReadGuard<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: holding a reader count on `self.shard` guarantees no
+        // writer holds (or can acquire) that shard, so no `&mut T` can
+        // exist concurrently.
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for StripedReadGuard<T> {
+    fn drop(&mut self) {
+        self.lock.shards[self.shard].fetch_sub(1, Ordering::Release);
+    }
+}
+
+impl<T> StripedReadGuard<T> {
+    /// Attempts to upgrade this read guard to a write guard in place, via a
+    /// single compare-and-swap on its own shard so there's no window for
+    /// another writer to slip in mid-upgrade. Succeeds only if this is the
+    /// only reader on its shard and every other shard is free; otherwise
+    /// returns the guard unchanged rather than blocking.
+    pub(crate) fn try_upgrade(self) -> Result<StripedWriteGuard<T>, Self> {
+        if self.lock.shards[self.shard]
+            .compare_exchange(1, WRITE_LOCKED, Ordering::AcqRel, Ordering::Relaxed)
+            .is_err()
+        {
+            return Err(self);
+        }
+
+        let mut acquired = Vec::with_capacity(SHARDS - 1);
+        for shard in 0..SHARDS {
+            if shard == self.shard {
+                continue;
+            }
+            if self.lock.shards[shard]
+                .compare_exchange(0, WRITE_LOCKED, Ordering::AcqRel, Ordering::Relaxed)
+                .is_ok()
+            {
+                acquired.push(shard);
+            } else {
+                for shard in acquired {
+                    self.lock.shards[shard].store(0, Ordering::Release);
+                }
+                // Restore our own shard to "one reader" -- nobody else
+                // could have touched it while it read `WRITE_LOCKED`.
+                self.lock.shards[self.shard].store(1, Ordering::Release);
+                return Err(self);
+            }
+        }
+
+        let lock = self.lock.clone();
+        std::mem::forget(self); // shards are now write-locked; skip the read-guard Drop.
+        Ok(StripedWriteGuard { lock })
+    }
+}
+
+/// A write guard holding every shard of a [`StripedRwLock`].
+pub(crate) struct StripedWriteGuard<T> {
+    lock: Arc<StripedRwLock<T>>,
+}
+
+impl<T> std::fmt::Debug for StripedWriteGuard<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StripedWriteGuard").finish_non_exhaustive()
+    }
+}
+
+impl<T> Deref for StripedWriteGuard<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: holding every shard write-locked excludes every reader
+        // and every other writer.
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for StripedWriteGuard<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: see `Deref` above.
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for StripedWriteGuard<T> {
+    fn drop(&mut self) {
+        for shard in &self.lock.shards {
+            shard.store(0, Ordering::Release);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn all_shards_free<T>(lock: &StripedRwLock<T>) -> bool {
+        lock.shards.iter().all(|s| s.load(Ordering::Relaxed) == 0)
+    }
+
+    #[test]
+    fn sole_reader_can_upgrade_and_the_write_guard_frees_every_shard_on_drop() {
+        let lock = Arc::new(StripedRwLock::new(0));
+        let read = lock.read();
+
+        let mut write = read.try_upgrade().expect("sole reader should upgrade");
+        *write = 42;
+        drop(write);
+
+        assert!(all_shards_free(&lock));
+        assert_eq!(*lock.read(), 42);
+    }
+
+    #[test]
+    fn upgrade_fails_and_returns_the_guard_unchanged_while_another_reader_is_active() {
+        let lock = Arc::new(StripedRwLock::new(0));
+        let read = lock.read();
+        let _other_reader = lock.read();
+
+        let read = match read.try_upgrade() {
+            Ok(_) => panic!("upgrade should not succeed with another reader active"),
+            Err(read) => read,
+        };
+
+        // The failed upgrade must not have disturbed any shard -- the
+        // original read guard is still valid and this lock isn't wedged.
+        assert_eq!(*read, 0);
+        drop(read);
+        drop(_other_reader);
+        assert!(all_shards_free(&lock));
+    }
+
+    #[test]
+    fn downgrading_after_an_upgrade_lets_another_reader_back_in() {
+        let lock = Arc::new(StripedRwLock::new(0));
+        let read = lock.read();
+        let write = read.try_upgrade().expect("sole reader should upgrade");
+
+        // Simulate Connection::lock's post-commit downgrade: drop the write
+        // guard and acquire a fresh read guard, rather than sitting on the
+        // write stripe forever.
+        drop(write);
+        let downgraded = lock.read();
+
+        let other = lock.read();
+        assert_eq!(*downgraded, 0);
+        assert_eq!(*other, 0);
+    }
+}
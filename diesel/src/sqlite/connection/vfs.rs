@@ -1,32 +1,332 @@
 extern crate libsqlite3_sys as ffi;
 
+use std::collections::HashMap;
 use std::io::{self, ErrorKind};
+use std::ops::Range;
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
 use super::memory::Memory;
-use sqlite_vfs::{LockKind, OpenKind, OpenOptions, Vfs};
+use super::snapshot::{PageMerkleTree, Snapshot};
+#[cfg(feature = "thread-safe")]
+use super::striped_lock::{StripedReadGuard, StripedRwLock, StripedWriteGuard};
+use lz4_flex::block::{compress_prepend_size, decompress_size_prepended};
+use sqlite_vfs::{LockKind, OpenKind, OpenOptions, Vfs, WalIndex};
 
 const SQLITE_SIZE_IN_BYTES: u64 = 8; // 8 byte
 
 const WASM_PAGE_SIZE_IN_BYTES: u64 = 64 * 1024; // 64KB
 
+/// Size of a single `-shm` region, per SQLite's WAL-index format.
+const WAL_SHM_REGION_SIZE: usize = 32 * 1024;
+
+/// How many `-shm` regions to reserve room for right after the WAL region's
+/// own size header, before the WAL file's actual frame data begins. SQLite
+/// maps only a couple of these in practice; this is a generous bound so the
+/// first `-shm` region never needs more than a small, fixed amount of extra
+/// stable memory.
+const WAL_SHM_RESERVED_REGIONS: u64 = 8;
+
+/// Number of SQLite WAL-index reader marks (`WAL_READ_LOCK(0..SQLITE_SHM_NLOCK-3)`).
+const WAL_READ_LOCKS: usize = 5;
+
+/// Page granularity used for compressed storage. The very first page (the
+/// database header, read before SQLite can tell us the real `page_size`
+/// pragma) is always addressed at this stride.
+const PAGE_SIZE: u64 = 4096;
+
+/// Each page directory slot records where a compressed page currently lives:
+/// `(physical_offset: u64, compressed_len: u32, capacity: u32)`. `capacity`
+/// tracks how large the blob slot actually is so a later, smaller write of
+/// the same page can reuse it without reallocating.
+const DIRECTORY_ENTRY_SIZE: u64 = 16;
+
+/// Entries a freshly allocated page directory starts with. Doubles (see
+/// `directory_capacity_for`) as the highest touched page index outgrows it,
+/// so a small database never pays for a directory sized for a huge one.
+const DIRECTORY_INITIAL_CAPACITY: u64 = 256; // 4096 bytes
+
+/// Size of a WAL region's fixed prefix: its own `SQLITE_SIZE_IN_BYTES` size
+/// header (mirroring `db_size`) plus the reserved `-shm` span, before the
+/// WAL file's actual frame data begins. This is a small, bounded size
+/// rather than a fixed address -- the region's actual base is allocated
+/// dynamically (see `ensure_wal_capacity`), not reserved up front.
+const WAL_FIXED_PREFIX_SIZE: u64 =
+    SQLITE_SIZE_IN_BYTES + WAL_SHM_REGION_SIZE as u64 * WAL_SHM_RESERVED_REGIONS;
+
+/// A root record's on-disk layout: `(db_size: u64, directory_base: u64,
+/// directory_capacity: u64)`. Two of these are kept side by side (see
+/// `HEADER_ROOT_A_OFFSET`/`HEADER_ROOT_B_OFFSET`) so a commit can build a
+/// whole new one without ever touching the one still live.
+const ROOT_RECORD_SIZE: u64 = 3 * SQLITE_SIZE_IN_BYTES;
+
+/// Offset, within the main db region, of root slot A.
+const HEADER_ROOT_A_OFFSET: u64 = 0;
+
+/// Offset, within the main db region, of root slot B.
+const HEADER_ROOT_B_OFFSET: u64 = HEADER_ROOT_A_OFFSET + ROOT_RECORD_SIZE;
+
+/// Offset, within the main db region, of the active-root flag: `0` means
+/// root A is the committed root, anything else means root B is. Flipping
+/// this single 8-byte word (see `commit_root`) is the one write a commit
+/// treats as atomic -- everything else a commit does only ever touches the
+/// *other* slot, never the currently active one.
+const HEADER_ACTIVE_ROOT_OFFSET: u64 = HEADER_ROOT_B_OFFSET + ROOT_RECORD_SIZE;
+
+/// Offset, within the main db region, of the `blob_bump` header: the next
+/// unused physical offset in the blob arena. This is also where a freshly
+/// built page directory lands -- both draw from the same bump pointer, so
+/// neither one needs a reserved span of its own. Deliberately outside the
+/// root record: losing track of it on a trap just leaks an allocation, it
+/// never corrupts a committed page, so it doesn't need the same atomicity.
+const HEADER_BLOB_BUMP_OFFSET: u64 = HEADER_ACTIVE_ROOT_OFFSET + SQLITE_SIZE_IN_BYTES;
+
+/// Offset, within the main db region, of the free list's current base
+/// offset. `0` means the free list still lives at its bootstrap location,
+/// `FREE_LIST_REGION_OFFSET` -- mirrors how `HEADER_WAL_BASE_OFFSET` tracks
+/// the WAL region's relocatable base.
+const HEADER_FREE_LIST_BASE_OFFSET: u64 = HEADER_BLOB_BUMP_OFFSET + SQLITE_SIZE_IN_BYTES;
+
+/// Offset, within the main db region, of the free list's current capacity,
+/// in bytes. `0` means the free list hasn't outgrown
+/// `FREE_LIST_INITIAL_CAPACITY_BYTES` yet. See `ensure_free_list_capacity`.
+const HEADER_FREE_LIST_CAPACITY_OFFSET: u64 = HEADER_FREE_LIST_BASE_OFFSET + SQLITE_SIZE_IN_BYTES;
+
+/// Bootstrap offset, within the main db region, of the persisted free list:
+/// an 8-byte entry count followed by that many `(physical_offset: u64,
+/// capacity: u32)` entries. Used as the free list's base until it first
+/// outgrows `FREE_LIST_INITIAL_CAPACITY_BYTES`, at which point it relocates
+/// like the page directory does -- see `ensure_free_list_capacity` -- rather
+/// than spilling past this fixed span into the header fields that follow it.
+const FREE_LIST_REGION_OFFSET: u64 = HEADER_FREE_LIST_CAPACITY_OFFSET + SQLITE_SIZE_IN_BYTES;
+
+/// Size of one persisted free-list entry: `(physical_offset: u64, capacity: u32)`.
+const FREE_LIST_ENTRY_SIZE: u64 = 12;
+
+/// Capacity the free list starts with at its bootstrap location, before it's
+/// ever had to relocate. The free list only grows with genuine
+/// fragmentation, which stays small in practice, so this is generous rather
+/// than tight; `ensure_free_list_capacity` doubles it (like
+/// `wal_capacity_for` does for the WAL region) on the rare database that
+/// outgrows it instead of ever writing past it.
+const FREE_LIST_INITIAL_CAPACITY_BYTES: u64 = 64 * 1024; // room for ~5461 entries
+
+/// Offset, within the main db region, of the WAL region's current base
+/// offset. `0` means no WAL region has ever been allocated yet -- it's
+/// allocated lazily, from the shared bump pointer, the first time the WAL
+/// file is actually touched (see `ensure_wal_capacity`).
+const HEADER_WAL_BASE_OFFSET: u64 = FREE_LIST_REGION_OFFSET + FREE_LIST_INITIAL_CAPACITY_BYTES;
+
+/// Offset, within the main db region, of the WAL region's current capacity,
+/// in bytes. See `ensure_wal_capacity`.
+const HEADER_WAL_CAPACITY_OFFSET: u64 = HEADER_WAL_BASE_OFFSET + SQLITE_SIZE_IN_BYTES;
+
+/// Bootstrap value for the shared bump pointer (`HEADER_BLOB_BUMP_OFFSET`)
+/// before anything has ever been allocated through it: right after the free
+/// list's reserved span and the WAL header words that follow it, rather than
+/// at a fixed multi-terabyte offset.
+const INITIAL_TAIL_OFFSET: u64 = HEADER_WAL_CAPACITY_OFFSET + SQLITE_SIZE_IN_BYTES;
+
+/// Capacity a freshly allocated WAL region starts with: its fixed prefix
+/// plus a little headroom for the first handful of frames, so a database
+/// that never opens in WAL mode never pays for one. Doubles (see
+/// `wal_capacity_for`) as frame data outgrows it, the same way the page
+/// directory's capacity doubles in `directory_capacity_for`.
+const WAL_INITIAL_CAPACITY: u64 = WAL_FIXED_PREFIX_SIZE + 64 * 1024;
+
+/// Which region of stable memory a [`Connection`] addresses. The main
+/// database and its WAL file share the same `Memory` but are given disjoint
+/// spans so frames never collide with pages. Unlike the main db region
+/// (always based at `0`), the WAL region's base is allocated dynamically --
+/// see `ensure_wal_capacity`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Region {
+    Main,
+    Wal,
+}
+
+/// A [`PageMerkleTree`] plus whether it's been rebuilt from the committed
+/// directory yet. The tree itself is heap-only state, so a fresh `PagesVfs`
+/// (e.g. after a canister upgrade) starts out with an empty one; `rebuilt`
+/// tracks whether `ensure_merkle_rebuilt` has already replayed the committed
+/// pages into it this session.
+#[derive(Debug, Default)]
+struct MerkleState {
+    tree: PageMerkleTree,
+    rebuilt: bool,
+}
+
 #[derive(Default, Debug)]
 pub(crate) struct PagesVfs<T: Memory> {
     lock_state: Arc<Mutex<LockState>>,
+    wal_locks: Arc<Mutex<WalLocks>>,
+    wal_regions: Arc<Mutex<WalRegions>>,
+    blob_allocator: Arc<Mutex<BlobAllocator>>,
+    root: Arc<Mutex<RootState>>,
+    merkle: Arc<Mutex<MerkleState>>,
+    wal_location: Arc<Mutex<WalLocation>>,
+    free_list_location: Arc<Mutex<FreeListLocation>>,
+    /// Backs the upgrade to `Exclusive` attempted in `Connection::lock`. Only meaningful with the `thread-safe` feature;
+    /// single-threaded canister builds never see real lock contention, so
+    /// they keep relying solely on `lock_state`'s bookkeeping.
+    #[cfg(feature = "thread-safe")]
+    shared_lock: Arc<StripedRwLock<()>>,
+    /// Whether pages are stored LZ4-compressed behind a page directory, or
+    /// written raw like before. Existing uncompressed databases must keep
+    /// opening, so this defaults to `false`.
+    compress: bool,
     memory: T,
 }
 
+impl<T: Memory> PagesVfs<T> {
+    pub(crate) fn new(memory: T, compress: bool) -> Self {
+        Self {
+            memory,
+            compress,
+            ..Default::default()
+        }
+    }
+}
+
 #[derive(Debug, Default)]
 struct LockState {
     read: usize,
     write: Option<bool>,
 }
 
+/// Byte-range locks over the WAL-index's reader-mark slots, mirroring
+/// SQLite's own `WAL_WRITE_LOCK` / `WAL_CKPT_LOCK` / `WAL_RECOVER_LOCK` /
+/// `WAL_READ_LOCK(n)` shared-memory locks.
+#[derive(Debug, Default)]
+struct WalLocks {
+    write: bool,
+    checkpoint: bool,
+    recover: bool,
+    readers: [usize; WAL_READ_LOCKS],
+    /// Whether each `readers` slot is held *exclusively* rather than shared.
+    /// `readers[i]` alone can't distinguish the two: both "one shared
+    /// reader" and "held exclusively" set it to `1`. Tracked separately so a
+    /// shared request against an exclusively-held slot -- e.g. a checkpoint
+    /// holding a `WAL_READ_LOCK(n)` exclusively to safely recycle frames --
+    /// is refused instead of silently granted.
+    reader_exclusive: [bool; WAL_READ_LOCKS],
+}
+
+/// How many `-shm` regions have been lazily allocated so far (see
+/// `PagesWalIndex::map`).
+#[derive(Debug, Default)]
+struct WalRegions {
+    count: usize,
+}
+
+/// Bump allocator for the variable-size compressed-page blob arena (the
+/// page directory draws from the same pointer when `commit_root` builds a
+/// new one), plus a free list of slots reclaimed from pages and directories
+/// that shrank or were superseded. Both fields are persisted (see
+/// `HEADER_BLOB_BUMP_OFFSET`, `FREE_LIST_REGION_OFFSET`) so a canister
+/// upgrade neither hands out an offset already in use nor leaks a freed
+/// slot forever; each field is `None` until the first access this session
+/// lazily loads it from stable memory.
+#[derive(Debug, Default)]
+struct BlobAllocator {
+    next: Option<u64>,
+    free_list: Option<Vec<(u64, u32)>>,
+}
+
+/// The WAL region's current `(base_offset, capacity)`, both persisted (see
+/// `HEADER_WAL_BASE_OFFSET`/`HEADER_WAL_CAPACITY_OFFSET`) so a canister
+/// upgrade remembers where the WAL file's data actually lives. `base ==
+/// None` (loaded as `0`) means no WAL region has been allocated yet; each
+/// field is `None` until the first access this session lazily loads it.
+#[derive(Debug, Default)]
+struct WalLocation {
+    base: Option<u64>,
+    capacity: Option<u64>,
+}
+
+/// The free list's current `(base_offset, capacity)`, both persisted (see
+/// `HEADER_FREE_LIST_BASE_OFFSET`/`HEADER_FREE_LIST_CAPACITY_OFFSET`) so a
+/// canister upgrade remembers where it actually lives. `base == None`
+/// (loaded as `0`) means the free list still lives at its bootstrap
+/// location, `FREE_LIST_REGION_OFFSET`; each field is `None` until the first
+/// access this session lazily loads it.
+#[derive(Debug, Default)]
+struct FreeListLocation {
+    base: Option<u64>,
+    capacity: Option<u64>,
+}
+
+/// The main db region's currently active root -- `(db_size, directory_base,
+/// directory_capacity)` -- plus which of the two persisted root slots
+/// (`HEADER_ROOT_A_OFFSET`/`HEADER_ROOT_B_OFFSET`) holds it. Lazily loaded
+/// from `HEADER_ACTIVE_ROOT_OFFSET` the first time it's needed this
+/// session; `commit_root` keeps it in sync with every flip it persists.
+#[derive(Debug, Default)]
+struct RootState {
+    active_slot: Option<u8>,
+    db_size: Option<u64>,
+    directory_base: Option<u64>,
+    directory_capacity: Option<u64>,
+}
+
+/// The main db region's in-flight transaction: every page written since the
+/// last `sync`, kept entirely on the heap rather than in stable memory.
+///
+/// This is the shadow-paging "current" table. Committing just means copying
+/// these entries into a new copy of the directory and flipping the active
+/// root (see `commit_root`);
+/// discarding them (an IC trap mid-transaction) takes no effort at all,
+/// because a trap already throws away all heap state for free -- unlike a
+/// second persisted table, there's no stale entry left behind to clean up or
+/// reason about. `open()` never consults this: a fresh `Connection` only
+/// ever sees the committed directory, exactly like loading from the last
+/// committed root.
+#[derive(Debug, Default)]
+struct PendingTransaction {
+    /// Page index -> freshly allocated, not-yet-committed blob slot
+    /// `(physical_offset, stored_len, capacity)`.
+    pages: HashMap<u64, (u64, u32, u32)>,
+    /// The region's size as of this transaction, if `set_len` or a growing
+    /// write has changed it.
+    size: Option<u64>,
+    /// Directory entries to clear at commit time (pages truncated away by
+    /// `set_len`, superseded by nothing).
+    cleared: Vec<u64>,
+    /// Blob slots superseded by a write in this transaction -- either a
+    /// committed slot a page moved away from, or one of this transaction's
+    /// own now-stale slots -- released back to the allocator at commit time.
+    freed: Vec<(u64, u32)>,
+}
+
+/// The real, cross-thread lock a [`Connection`] is holding under the
+/// `thread-safe` feature, mirroring its logical `lock: LockKind` -- a
+/// `Shared` or `Reserved` lock holds a read stripe, `Exclusive` holds the
+/// upgraded write stripe.
+#[cfg(feature = "thread-safe")]
+#[derive(Debug)]
+enum HeldLock {
+    Read(StripedReadGuard<()>),
+    Write(StripedWriteGuard<()>),
+}
+
 #[derive(Debug)]
 pub(crate) struct Connection<T: Memory> {
     lock_state: Arc<Mutex<LockState>>,
+    wal_locks: Arc<Mutex<WalLocks>>,
+    wal_regions: Arc<Mutex<WalRegions>>,
+    blob_allocator: Arc<Mutex<BlobAllocator>>,
+    root: Arc<Mutex<RootState>>,
+    merkle: Arc<Mutex<MerkleState>>,
+    pending: Arc<Mutex<PendingTransaction>>,
+    wal_location: Arc<Mutex<WalLocation>>,
+    free_list_location: Arc<Mutex<FreeListLocation>>,
+    #[cfg(feature = "thread-safe")]
+    shared_lock: Arc<StripedRwLock<()>>,
+    #[cfg(feature = "thread-safe")]
+    held: Option<HeldLock>,
+    compress: bool,
     lock: LockKind,
+    region: Region,
     memory: T,
 }
 
@@ -44,17 +344,44 @@ where
                 format!("unexpected database name `{}`; expected `main.db`", db),
             ));
         }
-        // Only main databases supported right now (no journal, wal, temporary, ...)
-        if opts.kind != OpenKind::MainDb {
-            return Err(io::Error::new(
-                ErrorKind::PermissionDenied,
-                "only main database supported right now (no journal, wal, ...)",
-            ));
-        }
+        // The main db lives in stable memory directly; the WAL file is
+        // routed to its own region, allocated lazily from the same shared
+        // bump pointer the moment it's actually touched (see
+        // `ensure_wal_capacity`). Journal and temporary files are still
+        // unsupported.
+        let region = match opts.kind {
+            OpenKind::MainDb => Region::Main,
+            OpenKind::Wal => Region::Wal,
+            _ => {
+                return Err(io::Error::new(
+                    ErrorKind::PermissionDenied,
+                    "only main database and wal supported right now (no journal, ...)",
+                ))
+            }
+        };
 
         let conn = Connection {
             lock_state: self.lock_state.clone(),
+            wal_locks: self.wal_locks.clone(),
+            wal_regions: self.wal_regions.clone(),
+            blob_allocator: self.blob_allocator.clone(),
+            root: self.root.clone(),
+            merkle: self.merkle.clone(),
+            // Each `Connection` gets its own in-flight transaction rather
+            // than sharing the VFS's: otherwise every other connection --
+            // including ones holding only `Shared` -- would see a writer's
+            // not-yet-`sync`'d pages through this same map, a dirty read
+            // that defeats the shadow paging above.
+            pending: Arc::new(Mutex::new(PendingTransaction::default())),
+            wal_location: self.wal_location.clone(),
+            free_list_location: self.free_list_location.clone(),
+            #[cfg(feature = "thread-safe")]
+            shared_lock: self.shared_lock.clone(),
+            #[cfg(feature = "thread-safe")]
+            held: None,
+            compress: self.compress,
             lock: LockKind::None,
+            region,
             memory: self.memory.clone(),
         };
 
@@ -90,44 +417,219 @@ impl<T> sqlite_vfs::DatabaseHandle for Connection<T>
 where
     T: Memory,
 {
-    type WalIndex = sqlite_vfs::WalDisabled;
+    type WalIndex = PagesWalIndex<T>;
 
     fn size(&self) -> Result<u64, io::Error> {
-        Ok(self.db_size())
+        Ok(self.region_size())
     }
 
     fn read_exact_at(&mut self, buf: &mut [u8], offset: u64) -> Result<(), io::Error> {
-        if self.memory.size() > 0 {
-            self.memory.read(offset + SQLITE_SIZE_IN_BYTES, buf);
+        if self.region == Region::Main {
+            if self.memory.size() == 0 {
+                return Ok(());
+            }
+            return self.read_paged(buf, offset);
+        }
+
+        // No WAL region has ever been allocated for this database yet, so
+        // there's nothing to read -- same as a brand-new, zero-length file.
+        let base = {
+            let mut location = self.wal_location.lock().unwrap();
+            load_wal_location(&self.memory, &mut location).0
+        };
+        if base == 0 {
+            return Ok(());
         }
+        self.memory.read(base + WAL_FIXED_PREFIX_SIZE + offset, buf);
         Ok(())
     }
 
     fn write_all_at(&mut self, buf: &[u8], offset: u64) -> Result<(), io::Error> {
         let size = offset + buf.len() as u64;
-        if size > self.db_size() {
-            self.memory.write(0, &size.to_be_bytes());
+        let grows = size > self.region_size();
+
+        if self.region == Region::Main {
+            // The new page version lands in a fresh blob slot and the
+            // committed directory is left untouched until `sync` -- see
+            // `PendingTransaction`.
+            if grows {
+                self.pending.lock().unwrap().size = Some(size);
+            }
+            self.write_paged(buf, offset)?;
+        } else {
+            // Ensure the WAL region (and the physical memory behind it)
+            // exists *before* writing anything into it, including its own
+            // size header -- the old fixed-offset scheme wrote the header
+            // first and grew capacity after, which let a write tear or
+            // panic out of bounds.
+            let relative_end = WAL_FIXED_PREFIX_SIZE + offset + buf.len() as u64;
+            let base = self.ensure_wal_capacity(relative_end)?;
+            if grows {
+                self.memory.write(base, &size.to_be_bytes());
+            }
+            self.memory.write(base + WAL_FIXED_PREFIX_SIZE + offset, buf);
         }
-        self.memory.write(offset + SQLITE_SIZE_IN_BYTES, buf);
+
+        // Every touched page -- including page 0 -- is shadow-paged, so its
+        // turn in the Merkle tree only comes from `commit_root`, once `sync`
+        // actually commits it -- see `touch_merkle_committed`. Touching it
+        // here would let any other `Connection` sharing this `merkle`
+        // observe a page that could still vanish if this transaction never
+        // reaches `sync`.
+
         Ok(())
     }
 
+    /// Publishes the in-flight transaction via `commit_root`. The WAL region
+    /// isn't shadow-paged (it's already SQLite's own crash-recovery log), so
+    /// this is a no-op there.
     fn sync(&mut self, _data_only: bool) -> Result<(), io::Error> {
-        // Everything is directly written to storage, so no extra steps necessary to sync.
-        Ok(())
+        if self.region != Region::Main {
+            return Ok(());
+        }
+
+        let pending = std::mem::take(&mut *self.pending.lock().unwrap());
+        if pending.pages.is_empty() && pending.cleared.is_empty() && pending.size.is_none() {
+            return Ok(());
+        }
+        self.commit_root(pending)
     }
 
-    fn set_len(&mut self, size: u64) -> Result<(), io::Error> {
-        let capacity = if self.memory.size() == 0 {
+    /// Atomically publishes a transaction by building a brand-new directory
+    /// -- copied from the active one, with this transaction's writes and
+    /// clears applied -- at a freshly bump-allocated location, then flipping
+    /// `HEADER_ACTIVE_ROOT_OFFSET` to point at it with a single 8-byte write.
+    /// That flip is the only write this VFS treats as atomic, and everything
+    /// before it only ever touches the *other* root slot and unreferenced
+    /// space, so a trap at any point leaves the previous root -- and every
+    /// page it points at -- fully intact. Only once the flip lands are the
+    /// superseded directory and page slots actually freed.
+    fn commit_root(&self, pending: PendingTransaction) -> Result<(), io::Error> {
+        let mut root = self.root.lock().unwrap();
+        let (old_size, old_base, old_capacity) = self.load_root(&mut root);
+        let new_size = pending.size.unwrap_or(old_size);
+
+        let touched = pending
+            .pages
+            .keys()
+            .chain(pending.cleared.iter())
+            .copied()
+            .max();
+        let new_capacity = match touched {
+            Some(page_index) => old_capacity.max(directory_capacity_for(page_index)),
+            None => old_capacity,
+        };
+
+        let directory_unchanged = pending.pages.is_empty() && pending.cleared.is_empty();
+        let new_base = if new_capacity == 0 {
             0
+        } else if directory_unchanged {
+            old_base
         } else {
-            self.stable_capacity() - SQLITE_SIZE_IN_BYTES
+            let mut allocator = self.blob_allocator.lock().unwrap();
+            let base = self.load_bump_pointer(&mut allocator);
+            let new_next = base + new_capacity * DIRECTORY_ENTRY_SIZE;
+            self.ensure_physical_capacity(new_next)?;
+            allocator.next = Some(new_next);
+            self.memory
+                .write(HEADER_BLOB_BUMP_OFFSET, &new_next.to_be_bytes());
+            drop(allocator);
+
+            if old_capacity > 0 {
+                let mut buf = vec![0u8; (old_capacity * DIRECTORY_ENTRY_SIZE) as usize];
+                self.memory.read(old_base, &mut buf);
+                self.memory.write(base, &buf);
+            }
+            base
         };
 
-        if size > capacity {
-            self.stable_grow_bytes(size - capacity)?;
-            self.memory.write(0, &size.to_be_bytes());
+        for (&page_index, &(physical_offset, stored_len, capacity)) in &pending.pages {
+            let offset = Self::directory_entry_offset(new_base, page_index);
+            self.memory.write(
+                offset,
+                &encode_directory_entry(physical_offset, stored_len, capacity),
+            );
         }
+        for &page_index in &pending.cleared {
+            let offset = Self::directory_entry_offset(new_base, page_index);
+            self.memory
+                .write(offset, &[0u8; DIRECTORY_ENTRY_SIZE as usize]);
+        }
+
+        let inactive_slot = 1 - root.active_slot.unwrap_or(0);
+        let mut record = [0u8; ROOT_RECORD_SIZE as usize];
+        record[0..8].copy_from_slice(&new_size.to_be_bytes());
+        record[8..16].copy_from_slice(&new_base.to_be_bytes());
+        record[16..24].copy_from_slice(&new_capacity.to_be_bytes());
+        self.memory
+            .write(Self::root_slot_offset(inactive_slot), &record);
+
+        // The atomic commit point: every write above only touched the
+        // inactive slot and unreferenced space, so this is the one write
+        // that can actually tear without corrupting anything observable.
+        self.memory.write(
+            HEADER_ACTIVE_ROOT_OFFSET,
+            &(inactive_slot as u64).to_be_bytes(),
+        );
+
+        root.active_slot = Some(inactive_slot);
+        root.db_size = Some(new_size);
+        root.directory_base = Some(new_base);
+        root.directory_capacity = Some(new_capacity);
+        drop(root);
+
+        // Only now that the flip has landed do these pages count as
+        // committed, so only now is it safe to let the (connection-wide
+        // shared) Merkle tree see them.
+        self.touch_merkle_committed(&pending);
+
+        if new_base != old_base && old_capacity > 0 {
+            self.free_blob_slot(old_base, (old_capacity * DIRECTORY_ENTRY_SIZE) as u32)?;
+        }
+        for (offset, capacity) in pending.freed {
+            self.free_blob_slot(offset, capacity)?;
+        }
+
+        Ok(())
+    }
+
+    fn set_len(&mut self, size: u64) -> Result<(), io::Error> {
+        if self.region != Region::Main {
+            let base = self.ensure_wal_capacity(WAL_FIXED_PREFIX_SIZE + size)?;
+            self.memory.write(base, &size.to_be_bytes());
+            return Ok(());
+        }
+
+        // Truncation only ever tombstones directory entries and blob slots
+        // for release at the next `sync`; the committed directory keeps
+        // describing the pre-truncation database until then.
+        let old_size = self.region_size();
+        if size < old_size {
+            let old_pages = (old_size + PAGE_SIZE - 1) / PAGE_SIZE;
+            let new_pages = (size + PAGE_SIZE - 1) / PAGE_SIZE;
+            let mut pending = self.pending.lock().unwrap();
+            for page_index in new_pages..old_pages {
+                if let Some((offset, _len, capacity)) = pending.pages.remove(&page_index) {
+                    pending.freed.push((offset, capacity));
+                    // This page was rewritten earlier in this same pending
+                    // transaction, but it may also still have a committed
+                    // directory entry from before this transaction started.
+                    // That committed entry has to be tombstoned too, or it
+                    // gets copied forward untouched into the new directory on
+                    // commit and silently reappears if the database is later
+                    // regrown past this page index.
+                    if self.read_directory_entry(page_index).is_some() {
+                        pending.cleared.push(page_index);
+                    }
+                } else if let Some((offset, _len, capacity)) = self.read_directory_entry(page_index)
+                {
+                    pending.freed.push((offset, capacity));
+                    pending.cleared.push(page_index);
+                }
+            }
+        }
+
+        self.pending.lock().unwrap().size = Some(size);
         Ok(())
     }
 
@@ -145,7 +647,13 @@ where
     }
 
     fn wal_index(&self, _readonly: bool) -> Result<Self::WalIndex, io::Error> {
-        Ok(sqlite_vfs::WalDisabled::default())
+        Ok(PagesWalIndex {
+            wal_locks: self.wal_locks.clone(),
+            wal_regions: self.wal_regions.clone(),
+            wal_location: self.wal_location.clone(),
+            blob_allocator: self.blob_allocator.clone(),
+            memory: self.memory.clone(),
+        })
     }
 
     fn unlock(&mut self, lock: LockKind) -> Result<bool, std::io::Error> {
@@ -165,36 +673,452 @@ where
 impl<T: Memory> Connection<T> {
     /// Gets capacity of the stable memory in bytes.
     fn stable_capacity(&self) -> u64 {
-        self.memory.size() << 16
+        stable_capacity(&self.memory)
     }
 
     /// Attempts to grow the memory by adding new pages.
     fn stable_grow_bytes(&self, size: u64) -> Result<u64, io::Error> {
-        let added_pages = (size as f64 / WASM_PAGE_SIZE_IN_BYTES as f64).ceil() as u64;
-        let g = self.memory.grow(added_pages);
-        if g == -1 {
-            Err(io::Error::new(io::ErrorKind::OutOfMemory, "out of memory"))
-        } else {
-            Ok(g.try_into().unwrap())
-        }
+        stable_grow_bytes(&self.memory, size)
     }
-    fn db_size(&self) -> u64 {
-        if self.memory.size() == 0 {
+
+    /// Size, in bytes, of whichever region (main db or WAL) this connection
+    /// addresses. For the main db, an in-flight transaction's own size
+    /// (read-your-own-write) takes priority over the last committed header;
+    /// otherwise this reads that region's own size header.
+    fn region_size(&self) -> u64 {
+        if self.region == Region::Main {
+            if let Some(size) = self.pending.lock().unwrap().size {
+                return size;
+            }
+            let mut root = self.root.lock().unwrap();
+            return self.load_root(&mut root).0;
+        }
+
+        let base = {
+            let mut location = self.wal_location.lock().unwrap();
+            load_wal_location(&self.memory, &mut location).0
+        };
+        if base == 0 {
             return 0;
         }
         let mut buf = [0u8; SQLITE_SIZE_IN_BYTES as usize];
-        self.memory.read(0, &mut buf);
+        self.memory.read(base, &mut buf);
         u64::from_be_bytes(buf)
     }
 
+    /// Grows stable memory, if necessary, so that `end_offset` is addressable.
+    fn ensure_physical_capacity(&self, end_offset: u64) -> Result<(), io::Error> {
+        ensure_physical_capacity(&self.memory, end_offset)
+    }
+
+    /// Loads the bump pointer from its persisted header the first time it's
+    /// needed this session; a no-op once `allocator.next` is already cached.
+    fn load_bump_pointer(&self, allocator: &mut BlobAllocator) -> u64 {
+        load_bump_pointer(&self.memory, allocator)
+    }
+
+    /// Ensures the WAL region has room for `relative_end` bytes past its
+    /// base -- allocating it for the first time, or relocating it to a
+    /// bigger span (doubling, see `wal_capacity_for`) if it's outgrown the
+    /// current one -- drawing from the exact same shared bump pointer the
+    /// main db's blob arena and page directory do, rather than the fixed
+    /// terabyte-scale span this region used to reserve up front. Returns
+    /// the region's (possibly just-relocated) base offset. Must be called
+    /// before any read or write at a WAL-region offset, never after, so the
+    /// physical memory behind that offset is guaranteed to already exist.
+    fn ensure_wal_capacity(&self, relative_end: u64) -> Result<u64, io::Error> {
+        ensure_wal_capacity(
+            &self.memory,
+            &self.wal_location,
+            &self.blob_allocator,
+            relative_end,
+        )
+    }
+
+    // --- Paged storage -------------------------------------------------------
+    //
+    // The main db region only ever holds a handful of small header fields.
+    // Every page -- including page 0, the SQLite header page -- lives in a
+    // blob arena (LZ4-compressed when `compress` is set, raw otherwise),
+    // with a directory mapping `page_index -> (physical_offset, stored_len,
+    // capacity)`. The directory is itself shadow-paged: `open()` only ever
+    // sees the directory the active root (see `RootState`) points at, and
+    // `commit_root` builds the next one at a freshly bump-allocated
+    // location rather than mutating it in place, so a write always lands
+    // somewhere a committed root doesn't yet point at.
+
+    fn directory_entry_offset(base: u64, page_index: u64) -> u64 {
+        base + page_index * DIRECTORY_ENTRY_SIZE
+    }
+
+    fn root_slot_offset(slot: u8) -> u64 {
+        if slot == 0 {
+            HEADER_ROOT_A_OFFSET
+        } else {
+            HEADER_ROOT_B_OFFSET
+        }
+    }
+
+    /// Loads the active root's `(db_size, directory_base, directory_capacity)`
+    /// the first time it's needed this session; a no-op once already cached.
+    fn load_root(&self, root: &mut RootState) -> (u64, u64, u64) {
+        if let (Some(size), Some(base), Some(capacity)) =
+            (root.db_size, root.directory_base, root.directory_capacity)
+        {
+            return (size, base, capacity);
+        }
+
+        let mut active_buf = [0u8; SQLITE_SIZE_IN_BYTES as usize];
+        if self.memory.size() > 0 {
+            self.memory.read(HEADER_ACTIVE_ROOT_OFFSET, &mut active_buf);
+        }
+        let active_slot = if u64::from_be_bytes(active_buf) == 0 {
+            0
+        } else {
+            1
+        };
+
+        let mut record = [0u8; ROOT_RECORD_SIZE as usize];
+        if self.memory.size() > 0 {
+            self.memory
+                .read(Self::root_slot_offset(active_slot), &mut record);
+        }
+        let size = u64::from_be_bytes(record[0..8].try_into().unwrap());
+        let base = u64::from_be_bytes(record[8..16].try_into().unwrap());
+        let capacity = u64::from_be_bytes(record[16..24].try_into().unwrap());
+
+        root.active_slot = Some(active_slot);
+        root.db_size = Some(size);
+        root.directory_base = Some(base);
+        root.directory_capacity = Some(capacity);
+        (size, base, capacity)
+    }
+
+    fn read_directory_entry(&self, page_index: u64) -> Option<(u64, u32, u32)> {
+        let (_, base, capacity) = {
+            let mut root = self.root.lock().unwrap();
+            self.load_root(&mut root)
+        };
+        if page_index >= capacity {
+            return None;
+        }
+        let mut buf = [0u8; DIRECTORY_ENTRY_SIZE as usize];
+        self.memory
+            .read(Self::directory_entry_offset(base, page_index), &mut buf);
+        decode_directory_entry(&buf)
+    }
+
+    /// Reads the current (decompressed, if applicable) content of a page --
+    /// this transaction's own pending write if it has one, else the last
+    /// committed version, else a zero-filled page if it was never written.
+    /// Page 0 (the SQLite header page) goes through exactly this same path:
+    /// it's just another directory entry, shadow-paged like any other page,
+    /// not a special fixed-offset passthrough.
+    fn read_page(&self, page_index: u64) -> Vec<u8> {
+        let slot = self
+            .pending
+            .lock()
+            .unwrap()
+            .pages
+            .get(&page_index)
+            .copied()
+            .or_else(|| self.read_directory_entry(page_index));
+
+        match slot {
+            None => vec![0u8; PAGE_SIZE as usize],
+            Some((physical_offset, stored_len, _capacity)) => {
+                let mut stored = vec![0u8; stored_len as usize];
+                self.memory.read(physical_offset, &mut stored);
+                if self.compress {
+                    decompress_page(&stored)
+                } else {
+                    stored
+                }
+            }
+        }
+    }
+
+    /// Writes the new version of a page to a freshly allocated blob slot and
+    /// records it in the pending transaction -- the committed directory
+    /// (and whatever slot it still points at) is left untouched until
+    /// `sync` publishes this write. This applies to page 0 (the SQLite
+    /// header page) exactly the same as every other page: SQLite's
+    /// file-format detection only requires page 0 to be *readable* before a
+    /// transaction is identified, not written through immediately, so a
+    /// trap between a page-0 write and the next `sync` can't leave a new
+    /// header describing a page count/structure the rest of the database
+    /// doesn't have yet.
+    fn store_page(&self, page_index: u64, page: &[u8]) -> Result<(), io::Error> {
+        let stored = if self.compress {
+            compress_page(page)
+        } else {
+            page.to_vec()
+        };
+        let needed_len = stored.len() as u32;
+
+        let (physical_offset, capacity) = self.allocate_blob_slot(needed_len)?;
+        self.ensure_physical_capacity(physical_offset + stored.len() as u64)?;
+        self.memory.write(physical_offset, &stored);
+
+        let mut pending = self.pending.lock().unwrap();
+        let new_slot = (physical_offset, needed_len, capacity);
+        if let Some(superseded) = pending.pages.insert(page_index, new_slot) {
+            // Never committed, so nothing else could be reading it.
+            pending.freed.push((superseded.0, superseded.2));
+        } else if let Some((offset, _len, capacity)) = self.read_directory_entry(page_index) {
+            // Still the committed version until `sync`; defer freeing it.
+            pending.freed.push((offset, capacity));
+        }
+        Ok(())
+    }
+
+    /// Loads the free list from its current base (see
+    /// `load_free_list_location`) the first time it's needed this session; a
+    /// no-op once it's already cached.
+    fn load_free_list(&self, allocator: &mut BlobAllocator) {
+        if allocator.free_list.is_some() {
+            return;
+        }
+
+        let base = {
+            let mut location = self.free_list_location.lock().unwrap();
+            load_free_list_location(&self.memory, &mut location).0
+        };
+
+        let mut count_buf = [0u8; SQLITE_SIZE_IN_BYTES as usize];
+        if self.memory.size() > 0 {
+            self.memory.read(base, &mut count_buf);
+        }
+        let count = u64::from_be_bytes(count_buf) as usize;
+
+        let mut list = Vec::with_capacity(count);
+        for i in 0..count {
+            let offset = base + SQLITE_SIZE_IN_BYTES + i as u64 * FREE_LIST_ENTRY_SIZE;
+            let mut buf = [0u8; FREE_LIST_ENTRY_SIZE as usize];
+            self.memory.read(offset, &mut buf);
+            let slot_offset = u64::from_be_bytes(buf[0..8].try_into().unwrap());
+            let capacity = u32::from_be_bytes(buf[8..12].try_into().unwrap());
+            list.push((slot_offset, capacity));
+        }
+        allocator.free_list = Some(list);
+    }
+
+    /// Writes `list` back in full, first relocating it to a bigger,
+    /// freshly bump-allocated span -- doubling, like `ensure_wal_capacity`
+    /// does for the WAL region -- if it's outgrown its current one. Called
+    /// after every free-list mutation so the list survives a canister
+    /// upgrade instead of leaking every freed slot forever, and so a long
+    /// enough free list never spills past its reserved span into whatever
+    /// header field happens to follow it.
+    fn persist_free_list(&self, list: &[(u64, u32)]) -> Result<(), io::Error> {
+        let needed = free_list_capacity_for(list.len());
+
+        let mut location = self.free_list_location.lock().unwrap();
+        let (mut base, capacity) = load_free_list_location(&self.memory, &mut location);
+        if needed > capacity {
+            let mut allocator = self.blob_allocator.lock().unwrap();
+            let new_base = self.load_bump_pointer(&mut allocator);
+            let new_next = new_base + needed;
+            self.ensure_physical_capacity(new_next)?;
+            allocator.next = Some(new_next);
+            self.memory
+                .write(HEADER_BLOB_BUMP_OFFSET, &new_next.to_be_bytes());
+            drop(allocator);
+
+            self.memory
+                .write(HEADER_FREE_LIST_BASE_OFFSET, &new_base.to_be_bytes());
+            self.memory
+                .write(HEADER_FREE_LIST_CAPACITY_OFFSET, &needed.to_be_bytes());
+            location.base = Some(new_base);
+            location.capacity = Some(needed);
+            base = new_base;
+        }
+        drop(location);
+
+        self.memory
+            .write(base, &(list.len() as u64).to_be_bytes());
+        for (i, (offset, capacity)) in list.iter().enumerate() {
+            let entry_offset = base + SQLITE_SIZE_IN_BYTES + i as u64 * FREE_LIST_ENTRY_SIZE;
+            let mut buf = [0u8; FREE_LIST_ENTRY_SIZE as usize];
+            buf[0..8].copy_from_slice(&offset.to_be_bytes());
+            buf[8..12].copy_from_slice(&capacity.to_be_bytes());
+            self.memory.write(entry_offset, &buf);
+        }
+        Ok(())
+    }
+
+    /// Translates a reduced blob-arena bump pointer into an actual
+    /// `Memory::shrink` call, reclaiming whole wasm pages -- but only when
+    /// the blob arena is provably the highest-address region in use right
+    /// now. `Memory` has no notion of regions; it would otherwise truncate
+    /// bytes belonging to whichever span happens to sit above the arena.
+    /// The WAL region (including its `-shm` arena) now draws from this same
+    /// bump pointer rather than a fixed offset far above it, so this stays
+    /// conservative and only fires once no `-shm` region has ever been
+    /// mapped, rather than trying to prove the WAL region's current span
+    /// doesn't overlap the reclaimed tail.
+    fn maybe_shrink_blob_arena(&self, new_next: u64) {
+        if self.wal_regions.lock().unwrap().count > 0 {
+            return;
+        }
+
+        let current_pages = self.memory.size();
+        let needed_pages = (new_next + WASM_PAGE_SIZE_IN_BYTES - 1) / WASM_PAGE_SIZE_IN_BYTES;
+        if needed_pages < current_pages {
+            self.memory.shrink(current_pages - needed_pages);
+        }
+    }
+
+    /// Releases a blob slot back to the allocator. If it sits immediately
+    /// before the bump pointer, the pointer is retracted instead of keeping
+    /// the slot on the free list -- repeating for whatever became newly
+    /// trailing, so a run of freed slots at the tail collapses all the way
+    /// back and the space is actually reclaimed rather than merely reused.
+    fn free_blob_slot(&self, offset: u64, capacity: u32) -> Result<(), io::Error> {
+        let mut allocator = self.blob_allocator.lock().unwrap();
+        self.load_free_list(&mut allocator);
+
+        let mut offset = offset;
+        let mut capacity = capacity;
+        loop {
+            let next = self.load_bump_pointer(&mut allocator);
+            if offset + capacity as u64 != next {
+                allocator
+                    .free_list
+                    .as_mut()
+                    .unwrap()
+                    .push((offset, capacity));
+                break;
+            }
+
+            allocator.next = Some(offset);
+            self.memory
+                .write(HEADER_BLOB_BUMP_OFFSET, &offset.to_be_bytes());
+            self.maybe_shrink_blob_arena(offset);
+
+            let list = allocator.free_list.as_mut().unwrap();
+            match list.iter().position(|&(o, c)| o + c as u64 == offset) {
+                Some(pos) => {
+                    let (o, c) = list.remove(pos);
+                    offset = o;
+                    capacity = c;
+                }
+                None => break,
+            }
+        }
+
+        let snapshot = allocator.free_list.clone().unwrap();
+        drop(allocator);
+        self.persist_free_list(&snapshot)
+    }
+
+    /// Returns `(physical_offset, capacity)` for a fresh blob slot able to
+    /// hold `needed_len` bytes, preferring a freed slot (first fit) over
+    /// bump-allocating new space at the end of the arena.
+    fn allocate_blob_slot(&self, needed_len: u32) -> Result<(u64, u32), io::Error> {
+        let mut allocator = self.blob_allocator.lock().unwrap();
+        self.load_free_list(&mut allocator);
+
+        let pos = allocator
+            .free_list
+            .as_ref()
+            .unwrap()
+            .iter()
+            .position(|&(_, cap)| cap >= needed_len);
+        if let Some(pos) = pos {
+            let list = allocator.free_list.as_mut().unwrap();
+            let (offset, cap) = list.remove(pos);
+            let snapshot = list.clone();
+            drop(allocator);
+            self.persist_free_list(&snapshot)?;
+            return Ok((offset, cap));
+        }
+
+        let next = self.load_bump_pointer(&mut allocator);
+        let offset = next;
+        let new_next = next + needed_len as u64;
+        allocator.next = Some(new_next);
+        self.memory
+            .write(HEADER_BLOB_BUMP_OFFSET, &new_next.to_be_bytes());
+
+        Ok((offset, needed_len))
+    }
+
+    fn read_paged(&mut self, buf: &mut [u8], offset: u64) -> Result<(), io::Error> {
+        let mut pos = offset;
+        let mut written = 0usize;
+
+        while written < buf.len() {
+            let page_index = pos / PAGE_SIZE;
+            let page_offset = (pos % PAGE_SIZE) as usize;
+            let page = self.read_page(page_index);
+
+            let take = (buf.len() - written).min(PAGE_SIZE as usize - page_offset);
+            buf[written..written + take].copy_from_slice(&page[page_offset..page_offset + take]);
+
+            written += take;
+            pos += take as u64;
+        }
+
+        Ok(())
+    }
+
+    fn write_paged(&mut self, buf: &[u8], offset: u64) -> Result<(), io::Error> {
+        let mut pos = offset;
+        let mut consumed = 0usize;
+
+        while consumed < buf.len() {
+            let page_index = pos / PAGE_SIZE;
+            let page_offset = (pos % PAGE_SIZE) as usize;
+            let take = (buf.len() - consumed).min(PAGE_SIZE as usize - page_offset);
+
+            let mut page = self.read_page(page_index);
+            page[page_offset..page_offset + take].copy_from_slice(&buf[consumed..consumed + take]);
+            self.store_page(page_index, &page)?;
+
+            consumed += take;
+            pos += take as u64;
+        }
+
+        Ok(())
+    }
+
     fn lock(&mut self, to: LockKind) -> bool {
         if self.lock == to {
             return true;
         }
 
+        // Under `thread-safe`, only the final Shared -> Exclusive transition
+        // needs to exclude every reader; Reserved only conflicts with other
+        // writers, which the `lock_state.write` bookkeeping below already
+        // enforces on its own. Attempting a full stripe upgrade for Reserved
+        // too would make it fail any time another connection is merely
+        // reading -- worse concurrency than the plain `Mutex` this feature
+        // exists to improve on. So win a real, non-blocking upgrade on the
+        // shared stripe lock only for Exclusive, before any bookkeeping
+        // below is touched -- if another connection already holds (or is
+        // mid-upgrading) the stripe, fail outright instead of blocking,
+        // exactly like SQLite expects here.
+        #[cfg(feature = "thread-safe")]
+        if matches!(to, LockKind::Exclusive) {
+            if let Some(HeldLock::Read(_)) = &self.held {
+                let guard = match self.held.take() {
+                    Some(HeldLock::Read(guard)) => guard,
+                    _ => unreachable!(),
+                };
+                match guard.try_upgrade() {
+                    Ok(write_guard) => self.held = Some(HeldLock::Write(write_guard)),
+                    Err(guard) => {
+                        self.held = Some(HeldLock::Read(guard));
+                        return false;
+                    }
+                }
+            }
+        }
+
         let mut lock_state = self.lock_state.lock().unwrap();
 
-        match to {
+        let ok = match to {
             LockKind::None => {
                 if self.lock == LockKind::Shared {
                     lock_state.read -= 1;
@@ -202,6 +1126,10 @@ impl<T: Memory> Connection<T> {
                     lock_state.write = None;
                 }
                 self.lock = LockKind::None;
+                #[cfg(feature = "thread-safe")]
+                {
+                    self.held = None;
+                }
                 true
             }
 
@@ -215,20 +1143,40 @@ impl<T: Memory> Connection<T> {
                     lock_state.write = None;
                 }
                 self.lock = LockKind::Shared;
+                #[cfg(feature = "thread-safe")]
+                {
+                    // A downgrade from Exclusive -- the normal post-commit
+                    // sequence -- is holding the full write stripe at this
+                    // point (a downgrade from Reserved still holds a read
+                    // stripe, which is kept as is). Drop it and acquire a fresh
+                    // read stripe rather than leaving every shard wedged
+                    // write-locked forever: `write()`/`read()` both spin
+                    // until every shard they need is free, so sitting on
+                    // the write stripe here would permanently block every
+                    // other connection's next lock/read.
+                    match self.held.take() {
+                        Some(HeldLock::Read(guard)) => self.held = Some(HeldLock::Read(guard)),
+                        Some(HeldLock::Write(guard)) => {
+                            drop(guard);
+                            self.held = Some(HeldLock::Read(self.shared_lock.read()));
+                        }
+                        None => self.held = Some(HeldLock::Read(self.shared_lock.read())),
+                    }
+                }
                 true
             }
 
             LockKind::Reserved => {
                 if lock_state.write.is_some() || self.lock != LockKind::Shared {
-                    return false;
-                }
-
-                if self.lock == LockKind::Shared {
-                    lock_state.read -= 1;
+                    false
+                } else {
+                    if self.lock == LockKind::Shared {
+                        lock_state.read -= 1;
+                    }
+                    lock_state.write = Some(false);
+                    self.lock = LockKind::Reserved;
+                    true
                 }
-                lock_state.write = Some(false);
-                self.lock = LockKind::Reserved;
-                true
             }
 
             LockKind::Pending => {
@@ -238,23 +1186,39 @@ impl<T: Memory> Connection<T> {
 
             LockKind::Exclusive => {
                 if lock_state.write.is_some() && self.lock <= LockKind::Shared {
-                    return false;
-                }
+                    false
+                } else {
+                    if self.lock == LockKind::Shared {
+                        lock_state.read -= 1;
+                    }
 
-                if self.lock == LockKind::Shared {
-                    lock_state.read -= 1;
+                    lock_state.write = Some(true);
+                    if lock_state.read == 0 {
+                        self.lock = LockKind::Exclusive;
+                        true
+                    } else {
+                        self.lock = LockKind::Pending;
+                        false
+                    }
                 }
+            }
+        };
 
-                lock_state.write = Some(true);
-                if lock_state.read == 0 {
-                    self.lock = LockKind::Exclusive;
-                    true
-                } else {
-                    self.lock = LockKind::Pending;
-                    false
-                }
+        // The stripe upgrade above only covers `shared_lock`;
+        // `lock_state`'s bookkeeping can still separately refuse
+        // the request (e.g. another connection already holds Reserved, so
+        // we end up Pending rather than Exclusive). Go back to holding just
+        // the read stripe rather than sitting on a now-pointless write lock
+        // until some unrelated future call happens to clear it.
+        #[cfg(feature = "thread-safe")]
+        if !ok && matches!(to, LockKind::Exclusive) {
+            if let Some(HeldLock::Write(guard)) = self.held.take() {
+                drop(guard);
+                self.held = Some(HeldLock::Read(self.shared_lock.read()));
             }
         }
+
+        ok
     }
 
     fn reserved(&self) -> bool {
@@ -265,6 +1229,192 @@ impl<T: Memory> Connection<T> {
         let lock_state = self.lock_state.lock().unwrap();
         lock_state.write.is_some()
     }
+
+    // --- Content-addressed snapshots ---------------------------------------------
+
+    /// Replays every committed page into the Merkle tree the first time
+    /// anything asks for a root, snapshot or diff, so a fresh `PagesVfs`
+    /// (e.g. right after a canister upgrade, once shadow paging has already
+    /// restored the actual page content) doesn't start from an empty tree
+    /// that silently disagrees with the real database. A no-op once this
+    /// has run this session.
+    fn ensure_merkle_rebuilt(&self) {
+        {
+            let state = self.merkle.lock().unwrap();
+            if state.rebuilt {
+                return;
+            }
+        }
+
+        let page_count = (self.region_size() + PAGE_SIZE - 1) / PAGE_SIZE;
+        let mut state = self.merkle.lock().unwrap();
+        if state.rebuilt {
+            return;
+        }
+        for page_index in 0..page_count {
+            let page = self.read_page(page_index);
+            state.tree.touch(page_index, &page);
+        }
+        state.rebuilt = true;
+    }
+
+    /// Rehashes every page a just-committed transaction touched or cleared.
+    /// Called from `commit_root` only after the atomic root flip has
+    /// landed, so `read_page` -- and therefore the Merkle tree -- only ever
+    /// sees this transaction's *committed* content, never a write that
+    /// could still have vanished if it had never reached `sync`.
+    fn touch_merkle_committed(&self, pending: &PendingTransaction) {
+        let touched: Vec<u64> = pending
+            .pages
+            .keys()
+            .chain(pending.cleared.iter())
+            .copied()
+            .collect();
+        if touched.is_empty() {
+            return;
+        }
+        self.ensure_merkle_rebuilt();
+
+        let mut state = self.merkle.lock().unwrap();
+        for page_index in touched {
+            let page = self.read_page(page_index);
+            state.tree.touch(page_index, &page);
+        }
+    }
+
+    /// The current Merkle root over every page in the database.
+    pub(crate) fn root(&self) -> [u8; 32] {
+        self.ensure_merkle_rebuilt();
+        self.merkle.lock().unwrap().tree.root()
+    }
+
+    /// Takes a cheap, owned snapshot of the current per-page content ids,
+    /// to later diff against with `export_changed_pages`.
+    pub(crate) fn snapshot(&self) -> Snapshot {
+        self.ensure_merkle_rebuilt();
+        self.merkle.lock().unwrap().tree.snapshot()
+    }
+
+    /// Every page whose content id differs from `since`, with its current
+    /// (decompressed) bytes, so only the delta needs to be shipped.
+    pub(crate) fn export_changed_pages(&self, since: &Snapshot) -> Vec<(u64, Vec<u8>)> {
+        self.ensure_merkle_rebuilt();
+        let changed = self.merkle.lock().unwrap().tree.changed_pages(since);
+        changed
+            .into_iter()
+            .map(|page_index| (page_index, self.read_page(page_index)))
+            .collect()
+    }
+}
+
+/// Applies an exported page diff (from `Connection::export_changed_pages`)
+/// directly to a fresh `Memory`, without going through a `Vfs`/`Connection`.
+/// Used to restore a database from a delta shipped by another replica,
+/// before any `PagesVfs` is constructed over it. Writes the committed
+/// directory and blob arena directly (there's no `Connection` yet to own a
+/// `PendingTransaction`). `compress` must match the `compress` flag the
+/// `PagesVfs` that will later open this `Memory` is constructed with --
+/// `read_page` has no way to tell a raw blob from a compressed one other
+/// than that flag, so a mismatch here silently corrupts every imported page.
+pub(crate) fn import_pages<T: Memory>(memory: &T, pages: Vec<(u64, Vec<u8>)>, compress: bool) {
+    fn ensure_capacity<T: Memory>(memory: &T, end_offset: u64) {
+        let capacity = memory.size() << 16;
+        if end_offset > capacity {
+            let added_pages =
+                ((end_offset - capacity) as f64 / WASM_PAGE_SIZE_IN_BYTES as f64).ceil() as u64;
+            memory.grow(added_pages);
+        }
+    }
+
+    // No `Connection` exists yet to contend over which root is active, so
+    // this updates the currently active slot in place rather than building
+    // a new one to flip to -- the atomicity `commit_root` provides only
+    // matters once a live transaction can race a trap.
+    let active_slot = {
+        let mut buf = [0u8; SQLITE_SIZE_IN_BYTES as usize];
+        if memory.size() > 0 {
+            memory.read(HEADER_ACTIVE_ROOT_OFFSET, &mut buf);
+        }
+        if u64::from_be_bytes(buf) == 0 {
+            HEADER_ROOT_A_OFFSET
+        } else {
+            HEADER_ROOT_B_OFFSET
+        }
+    };
+
+    let mut record = [0u8; ROOT_RECORD_SIZE as usize];
+    if memory.size() > 0 {
+        memory.read(active_slot, &mut record);
+    }
+    let mut db_size = u64::from_be_bytes(record[0..8].try_into().unwrap());
+    let mut directory_base = u64::from_be_bytes(record[8..16].try_into().unwrap());
+    let mut directory_capacity = u64::from_be_bytes(record[16..24].try_into().unwrap());
+
+    let mut bump = {
+        let mut buf = [0u8; SQLITE_SIZE_IN_BYTES as usize];
+        if memory.size() > 0 {
+            memory.read(HEADER_BLOB_BUMP_OFFSET, &mut buf);
+        }
+        let persisted = u64::from_be_bytes(buf);
+        if persisted == 0 {
+            INITIAL_TAIL_OFFSET
+        } else {
+            persisted
+        }
+    };
+
+    // Grow/relocate the directory once up front to cover every page in this
+    // import, rather than repeatedly relocating it page by page below.
+    let max_page_index = pages.iter().map(|(i, _)| *i).max().unwrap_or(0);
+    if max_page_index >= directory_capacity {
+        let new_capacity = directory_capacity_for(max_page_index);
+        let new_base = bump;
+        let new_len = new_capacity * DIRECTORY_ENTRY_SIZE;
+        ensure_capacity(memory, new_base + new_len);
+
+        if directory_capacity > 0 {
+            let mut buf = vec![0u8; (directory_capacity * DIRECTORY_ENTRY_SIZE) as usize];
+            memory.read(directory_base, &mut buf);
+            memory.write(new_base, &buf);
+        }
+
+        bump = new_base + new_len;
+        directory_base = new_base;
+        directory_capacity = new_capacity;
+
+        ensure_capacity(memory, HEADER_BLOB_BUMP_OFFSET + SQLITE_SIZE_IN_BYTES);
+        memory.write(HEADER_BLOB_BUMP_OFFSET, &bump.to_be_bytes());
+    }
+
+    for (page_index, page) in pages {
+        db_size = db_size.max((page_index + 1) * PAGE_SIZE);
+
+        let stored = if compress { compress_page(&page) } else { page };
+
+        let physical_offset = bump;
+        let stored_len = stored.len() as u64;
+        ensure_capacity(memory, physical_offset + stored_len);
+        memory.write(physical_offset, &stored);
+
+        bump += stored_len;
+        ensure_capacity(memory, HEADER_BLOB_BUMP_OFFSET + SQLITE_SIZE_IN_BYTES);
+        memory.write(HEADER_BLOB_BUMP_OFFSET, &bump.to_be_bytes());
+
+        let entry_offset = directory_base + page_index * DIRECTORY_ENTRY_SIZE;
+        ensure_capacity(memory, entry_offset + DIRECTORY_ENTRY_SIZE);
+        let mut entry = [0u8; DIRECTORY_ENTRY_SIZE as usize];
+        entry[0..8].copy_from_slice(&physical_offset.to_be_bytes());
+        entry[8..12].copy_from_slice(&(stored_len as u32).to_be_bytes());
+        entry[12..16].copy_from_slice(&(stored_len as u32).to_be_bytes());
+        memory.write(entry_offset, &entry);
+    }
+
+    ensure_capacity(memory, active_slot + ROOT_RECORD_SIZE);
+    let mut record = [0u8; ROOT_RECORD_SIZE as usize];
+    record[0..8].copy_from_slice(&db_size.to_be_bytes());
+    record[8..16].copy_from_slice(&directory_base.to_be_bytes());
+    record[16..24].copy_from_slice(&directory_capacity.to_be_bytes());
+    memory.write(active_slot, &record);
 }
 
 impl<T: Memory> Drop for Connection<T> {
@@ -275,6 +1425,553 @@ impl<T: Memory> Drop for Connection<T> {
     }
 }
 
+/// A `WalIndex` whose `-shm` regions are backed by stable memory rather than
+/// an mmap'd file, shared by every `Connection` through the same
+/// `Arc<Mutex<WalRegions>>`.
+#[derive(Debug)]
+pub(crate) struct PagesWalIndex<T: Memory> {
+    wal_locks: Arc<Mutex<WalLocks>>,
+    wal_regions: Arc<Mutex<WalRegions>>,
+    wal_location: Arc<Mutex<WalLocation>>,
+    blob_allocator: Arc<Mutex<BlobAllocator>>,
+    memory: T,
+}
+
+impl<T: Memory> WalIndex for PagesWalIndex<T> {
+    fn enabled() -> bool {
+        true
+    }
+
+    fn map(
+        &mut self,
+        region: u32,
+        extend: bool,
+    ) -> Result<Option<[u8; WAL_SHM_REGION_SIZE]>, io::Error> {
+        if region as u64 >= WAL_SHM_RESERVED_REGIONS {
+            // `shm_relative_offset` below is only in bounds for
+            // `region < WAL_SHM_RESERVED_REGIONS`; at `region ==
+            // WAL_SHM_RESERVED_REGIONS` it would equal `WAL_FIXED_PREFIX_SIZE`
+            // exactly, aliasing this region's shm bytes directly onto the
+            // WAL's actual frame data instead of a dedicated reserved span.
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("wal index region {region} exceeds the {WAL_SHM_RESERVED_REGIONS} reserved -shm regions"),
+            ));
+        }
+
+        let mut regions = self.wal_regions.lock().unwrap();
+        let region = region as usize;
+
+        if region >= regions.count {
+            if !extend {
+                return Ok(None);
+            }
+            regions.count = region + 1;
+        }
+        drop(regions);
+
+        // The `-shm` arena sits right after the WAL region's own size
+        // header and is itself part of `WAL_FIXED_PREFIX_SIZE`, so it's
+        // already accounted for the first time the region is allocated --
+        // this never needs its own physical-growth call.
+        let shm_relative_offset =
+            SQLITE_SIZE_IN_BYTES + region as u64 * WAL_SHM_REGION_SIZE as u64;
+        let relative_end = shm_relative_offset + WAL_SHM_REGION_SIZE as u64;
+        let base = ensure_wal_capacity(
+            &self.memory,
+            &self.wal_location,
+            &self.blob_allocator,
+            relative_end,
+        )?;
+
+        let mut buf = [0u8; WAL_SHM_REGION_SIZE];
+        self.memory.read(base + shm_relative_offset, &mut buf);
+        Ok(Some(buf))
+    }
+
+    fn lock(
+        &mut self,
+        locks: Range<u8>,
+        lock: sqlite_vfs::WalIndexLock,
+    ) -> Result<bool, io::Error> {
+        let mut wal_locks = self.wal_locks.lock().unwrap();
+
+        match lock {
+            sqlite_vfs::WalIndexLock::None => {
+                for n in locks {
+                    release_wal_lock(&mut wal_locks, n);
+                }
+                Ok(true)
+            }
+            sqlite_vfs::WalIndexLock::Shared => {
+                // Shared (reader) locks are only ever requested one at a time.
+                if let Some(n) = locks.into_iter().next() {
+                    Ok(acquire_wal_reader_lock(&mut wal_locks, n))
+                } else {
+                    Ok(true)
+                }
+            }
+            sqlite_vfs::WalIndexLock::Exclusive => Ok(locks
+                .into_iter()
+                .all(|n| acquire_wal_exclusive_lock(&mut wal_locks, n))),
+        }
+    }
+
+    fn delete(self) -> Result<(), io::Error> {
+        Ok(())
+    }
+}
+
+/// SQLite addresses the WAL-index locks as a single array, per `wal.c`:
+/// index `0` is `WAL_WRITE_LOCK`, `1` is `WAL_CKPT_LOCK`, `2` is
+/// `WAL_RECOVER_LOCK`, and `WAL_READ_LOCK_BASE..+WAL_READ_LOCKS` are the
+/// `WAL_READ_LOCK(0..)` reader marks. `sqlite_vfs::WalIndex::lock` passes
+/// these indices through verbatim, so this layout has to match exactly.
+const WAL_WRITE_LOCK: u8 = 0;
+const WAL_CKPT_LOCK: u8 = 1;
+const WAL_RECOVER_LOCK: u8 = 2;
+const WAL_READ_LOCK_BASE: u8 = 3;
+
+fn acquire_wal_reader_lock(locks: &mut WalLocks, n: u8) -> bool {
+    match n {
+        WAL_WRITE_LOCK => {
+            !locks.write && {
+                locks.write = true;
+                true
+            }
+        }
+        WAL_CKPT_LOCK => {
+            !locks.checkpoint && {
+                locks.checkpoint = true;
+                true
+            }
+        }
+        WAL_RECOVER_LOCK => {
+            !locks.recover && {
+                locks.recover = true;
+                true
+            }
+        }
+        n => {
+            let i = (n - WAL_READ_LOCK_BASE) as usize;
+            if locks.reader_exclusive[i] {
+                false
+            } else {
+                locks.readers[i] += 1;
+                true
+            }
+        }
+    }
+}
+
+fn acquire_wal_exclusive_lock(locks: &mut WalLocks, n: u8) -> bool {
+    match n {
+        WAL_WRITE_LOCK | WAL_CKPT_LOCK | WAL_RECOVER_LOCK => acquire_wal_reader_lock(locks, n),
+        n => {
+            let i = (n - WAL_READ_LOCK_BASE) as usize;
+            if locks.readers[i] > 0 {
+                false
+            } else {
+                locks.readers[i] = 1;
+                locks.reader_exclusive[i] = true;
+                true
+            }
+        }
+    }
+}
+
+fn release_wal_lock(locks: &mut WalLocks, n: u8) {
+    match n {
+        WAL_WRITE_LOCK => locks.write = false,
+        WAL_CKPT_LOCK => locks.checkpoint = false,
+        WAL_RECOVER_LOCK => locks.recover = false,
+        n => {
+            let i = (n - WAL_READ_LOCK_BASE) as usize;
+            locks.reader_exclusive[i] = false;
+            locks.readers[i] = locks.readers[i].saturating_sub(1);
+        }
+    }
+}
+
+fn compress_page(page: &[u8]) -> Vec<u8> {
+    compress_prepend_size(page)
+}
+
+fn decompress_page(compressed: &[u8]) -> Vec<u8> {
+    decompress_size_prepended(compressed).unwrap_or_else(|_| vec![0u8; PAGE_SIZE as usize])
+}
+
+/// Encodes a page directory entry's `(physical_offset, stored_len, capacity)`
+/// into its on-disk `DIRECTORY_ENTRY_SIZE`-byte big-endian layout.
+fn encode_directory_entry(
+    physical_offset: u64,
+    stored_len: u32,
+    capacity: u32,
+) -> [u8; DIRECTORY_ENTRY_SIZE as usize] {
+    let mut buf = [0u8; DIRECTORY_ENTRY_SIZE as usize];
+    buf[0..8].copy_from_slice(&physical_offset.to_be_bytes());
+    buf[8..12].copy_from_slice(&stored_len.to_be_bytes());
+    buf[12..16].copy_from_slice(&capacity.to_be_bytes());
+    buf
+}
+
+/// Decodes a page directory entry back from its on-disk layout; `None` for
+/// an all-zero entry (a page that was never written, or was tombstoned).
+fn decode_directory_entry(buf: &[u8; DIRECTORY_ENTRY_SIZE as usize]) -> Option<(u64, u32, u32)> {
+    let physical_offset = u64::from_be_bytes(buf[0..8].try_into().unwrap());
+    let stored_len = u32::from_be_bytes(buf[8..12].try_into().unwrap());
+    let capacity = u32::from_be_bytes(buf[12..16].try_into().unwrap());
+    if stored_len == 0 && capacity == 0 {
+        None
+    } else {
+        Some((physical_offset, stored_len, capacity))
+    }
+}
+
+/// Smallest capacity, a multiple of `DIRECTORY_INITIAL_CAPACITY` by a power
+/// of two, that can address `page_index`.
+fn directory_capacity_for(page_index: u64) -> u64 {
+    let mut capacity = DIRECTORY_INITIAL_CAPACITY;
+    while capacity <= page_index {
+        capacity *= 2;
+    }
+    capacity
+}
+
+/// Smallest capacity, a multiple of `WAL_INITIAL_CAPACITY` by a power of
+/// two, that covers `relative_end` bytes past the WAL region's base.
+fn wal_capacity_for(relative_end: u64) -> u64 {
+    let mut capacity = WAL_INITIAL_CAPACITY;
+    while capacity < relative_end {
+        capacity *= 2;
+    }
+    capacity
+}
+
+/// Smallest capacity, a multiple of `FREE_LIST_INITIAL_CAPACITY_BYTES` by a
+/// power of two, that covers persisting `entry_count` free-list entries.
+fn free_list_capacity_for(entry_count: usize) -> u64 {
+    let needed = SQLITE_SIZE_IN_BYTES + entry_count as u64 * FREE_LIST_ENTRY_SIZE;
+    let mut capacity = FREE_LIST_INITIAL_CAPACITY_BYTES;
+    while capacity < needed {
+        capacity *= 2;
+    }
+    capacity
+}
+
+/// Gets capacity of the stable memory in bytes.
+fn stable_capacity<T: Memory>(memory: &T) -> u64 {
+    memory.size() << 16
+}
+
+/// Attempts to grow the memory by adding new pages.
+fn stable_grow_bytes<T: Memory>(memory: &T, size: u64) -> Result<u64, io::Error> {
+    let added_pages = (size as f64 / WASM_PAGE_SIZE_IN_BYTES as f64).ceil() as u64;
+    let g = memory.grow(added_pages);
+    if g == -1 {
+        Err(io::Error::new(io::ErrorKind::OutOfMemory, "out of memory"))
+    } else {
+        Ok(g.try_into().unwrap())
+    }
+}
+
+/// Grows stable memory, if necessary, so that `end_offset` is addressable.
+fn ensure_physical_capacity<T: Memory>(memory: &T, end_offset: u64) -> Result<(), io::Error> {
+    let capacity = stable_capacity(memory);
+    if end_offset > capacity {
+        stable_grow_bytes(memory, end_offset - capacity)?;
+    }
+    Ok(())
+}
+
+/// Loads the bump pointer from its persisted header the first time it's
+/// needed this session; a no-op once `allocator.next` is already cached.
+fn load_bump_pointer<T: Memory>(memory: &T, allocator: &mut BlobAllocator) -> u64 {
+    if let Some(next) = allocator.next {
+        return next;
+    }
+    let mut buf = [0u8; SQLITE_SIZE_IN_BYTES as usize];
+    if memory.size() > 0 {
+        memory.read(HEADER_BLOB_BUMP_OFFSET, &mut buf);
+    }
+    let persisted = u64::from_be_bytes(buf);
+    let next = if persisted == 0 {
+        INITIAL_TAIL_OFFSET
+    } else {
+        persisted
+    };
+    allocator.next = Some(next);
+    next
+}
+
+/// Loads the WAL region's current `(base, capacity)` from its persisted
+/// header the first time it's needed this session; `(0, 0)` means no WAL
+/// region has been allocated yet. A no-op once already cached.
+fn load_wal_location<T: Memory>(memory: &T, location: &mut WalLocation) -> (u64, u64) {
+    if let (Some(base), Some(capacity)) = (location.base, location.capacity) {
+        return (base, capacity);
+    }
+    let mut base_buf = [0u8; SQLITE_SIZE_IN_BYTES as usize];
+    let mut capacity_buf = [0u8; SQLITE_SIZE_IN_BYTES as usize];
+    if memory.size() > 0 {
+        memory.read(HEADER_WAL_BASE_OFFSET, &mut base_buf);
+        memory.read(HEADER_WAL_CAPACITY_OFFSET, &mut capacity_buf);
+    }
+    let base = u64::from_be_bytes(base_buf);
+    let capacity = u64::from_be_bytes(capacity_buf);
+    location.base = Some(base);
+    location.capacity = Some(capacity);
+    (base, capacity)
+}
+
+/// Loads the free list's current `(base, capacity)` from its persisted
+/// header the first time it's needed this session; `(0, 0)` bootstraps to
+/// `(FREE_LIST_REGION_OFFSET, FREE_LIST_INITIAL_CAPACITY_BYTES)`, its
+/// location before it's ever had to relocate. A no-op once already cached.
+fn load_free_list_location<T: Memory>(memory: &T, location: &mut FreeListLocation) -> (u64, u64) {
+    if let (Some(base), Some(capacity)) = (location.base, location.capacity) {
+        return (base, capacity);
+    }
+    let mut base_buf = [0u8; SQLITE_SIZE_IN_BYTES as usize];
+    let mut capacity_buf = [0u8; SQLITE_SIZE_IN_BYTES as usize];
+    if memory.size() > 0 {
+        memory.read(HEADER_FREE_LIST_BASE_OFFSET, &mut base_buf);
+        memory.read(HEADER_FREE_LIST_CAPACITY_OFFSET, &mut capacity_buf);
+    }
+    let persisted_base = u64::from_be_bytes(base_buf);
+    let persisted_capacity = u64::from_be_bytes(capacity_buf);
+    let base = if persisted_base == 0 {
+        FREE_LIST_REGION_OFFSET
+    } else {
+        persisted_base
+    };
+    let capacity = if persisted_capacity == 0 {
+        FREE_LIST_INITIAL_CAPACITY_BYTES
+    } else {
+        persisted_capacity
+    };
+    location.base = Some(base);
+    location.capacity = Some(capacity);
+    (base, capacity)
+}
+
+/// Ensures the WAL region has room for `relative_end` bytes past its base,
+/// bump-allocating it (or relocating it to a bigger span, doubling each
+/// time -- see `wal_capacity_for`) from the exact same shared tail the main
+/// db's blob arena and page directory draw from, instead of the fixed
+/// terabyte-scale span this used to reserve up front. Returns the region's
+/// (possibly just-relocated) base offset.
+///
+/// A relocated span's old bytes are left in place rather than freed back
+/// to the allocator: the WAL region only grows a handful of times over a
+/// connection's life (each doubling), so the waste stays bounded, unlike
+/// the page directory's relocations, which happen on every commit that
+/// crosses a capacity boundary and so are worth reclaiming via
+/// `free_blob_slot`.
+fn ensure_wal_capacity<T: Memory>(
+    memory: &T,
+    wal_location: &Mutex<WalLocation>,
+    blob_allocator: &Mutex<BlobAllocator>,
+    relative_end: u64,
+) -> Result<u64, io::Error> {
+    let mut location = wal_location.lock().unwrap();
+    let (base, capacity) = load_wal_location(memory, &mut location);
+    if base != 0 && relative_end <= capacity {
+        return Ok(base);
+    }
+
+    let new_capacity = wal_capacity_for(relative_end);
+
+    let mut allocator = blob_allocator.lock().unwrap();
+    let new_base = load_bump_pointer(memory, &mut allocator);
+    let new_next = new_base + new_capacity;
+    ensure_physical_capacity(memory, new_next)?;
+    allocator.next = Some(new_next);
+    memory.write(HEADER_BLOB_BUMP_OFFSET, &new_next.to_be_bytes());
+    drop(allocator);
+
+    if base != 0 && capacity > 0 {
+        let mut buf = vec![0u8; capacity as usize];
+        memory.read(base, &mut buf);
+        memory.write(new_base, &buf);
+    }
+
+    memory.write(HEADER_WAL_BASE_OFFSET, &new_base.to_be_bytes());
+    memory.write(HEADER_WAL_CAPACITY_OFFSET, &new_capacity.to_be_bytes());
+    location.base = Some(new_base);
+    location.capacity = Some(new_capacity);
+
+    Ok(new_base)
+}
+
 fn conn_sleep(ms: u32) {
     std::thread::sleep(Duration::from_secs(ms.into()));
 }
+
+#[cfg(test)]
+mod tests {
+    use super::super::memory::VectorMemory;
+    use super::*;
+    use sqlite_vfs::{DatabaseHandle, OpenAccess};
+
+    fn open_main(vfs: &PagesVfs<VectorMemory>) -> Connection<VectorMemory> {
+        vfs.open(
+            "main.db",
+            OpenOptions {
+                kind: OpenKind::MainDb,
+                access: OpenAccess::Write,
+            },
+        )
+        .unwrap()
+    }
+
+    fn open_wal(vfs: &PagesVfs<VectorMemory>) -> Connection<VectorMemory> {
+        vfs.open(
+            "main.db",
+            OpenOptions {
+                kind: OpenKind::Wal,
+                access: OpenAccess::Write,
+            },
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn wal_region_reads_and_writes_without_a_fixed_terabyte_reservation() {
+        let vfs = PagesVfs::new(VectorMemory::default(), false);
+
+        // Grow the main db's blob arena first -- the exact situation that
+        // used to force the Wal region's first access out to a fixed
+        // 1 TiB offset regardless of how far the arena had actually grown.
+        let mut main = open_main(&vfs);
+        main.write_all_at(&[1u8; PAGE_SIZE as usize], 0).unwrap();
+        main.sync(false).unwrap();
+
+        let mut wal = open_wal(&vfs);
+        wal.write_all_at(b"frame-one", 0).unwrap();
+        wal.write_all_at(b"frame-two", 16).unwrap();
+        assert_eq!(wal.size().unwrap(), 25);
+
+        let mut buf = [0u8; 9];
+        wal.read_exact_at(&mut buf, 0).unwrap();
+        assert_eq!(&buf, b"frame-one");
+        wal.read_exact_at(&mut buf, 16).unwrap();
+        assert_eq!(&buf, b"frame-two");
+    }
+
+    #[test]
+    fn a_trapped_transaction_leaves_the_committed_root_and_page_untouched() {
+        let vfs = PagesVfs::new(VectorMemory::default(), false);
+
+        // Page 1, like every page, is shadow-paged, so it exercises
+        // `PendingTransaction` discard semantics.
+        let mut writer = open_main(&vfs);
+        writer
+            .write_all_at(&[1u8; PAGE_SIZE as usize], PAGE_SIZE)
+            .unwrap();
+        writer.sync(false).unwrap();
+        let committed_root = writer.root();
+
+        // Stand in for an IC trap partway through the next transaction:
+        // the write lands in this connection's own pending state, but
+        // `sync` never runs, and the connection itself is dropped.
+        writer
+            .write_all_at(&[2u8; PAGE_SIZE as usize], PAGE_SIZE)
+            .unwrap();
+        drop(writer);
+
+        // A fresh connection -- sharing the same committed root and Merkle
+        // tree, but never the dropped connection's `pending` -- must only
+        // ever observe the last committed page.
+        let mut reader = open_main(&vfs);
+        assert_eq!(reader.root(), committed_root);
+
+        let mut page = vec![0u8; PAGE_SIZE as usize];
+        reader.read_exact_at(&mut page, PAGE_SIZE).unwrap();
+        assert_eq!(page, vec![1u8; PAGE_SIZE as usize]);
+    }
+
+    #[test]
+    fn a_shrunk_and_regrown_database_reuses_the_persisted_free_list() {
+        let vfs = PagesVfs::new(VectorMemory::default(), false);
+        let mut conn = open_main(&vfs);
+
+        // Write page 2 before page 1, so page 2's blob slot physically
+        // precedes page 1's -- truncating page 2 away later then frees a
+        // slot that *isn't* the arena's tail, so it can only be reclaimed by
+        // actually landing on the free list, not via the tail-collapse
+        // shortcut in `free_blob_slot`.
+        conn.write_all_at(&[2u8; PAGE_SIZE as usize], 2 * PAGE_SIZE)
+            .unwrap();
+        conn.write_all_at(&[1u8; PAGE_SIZE as usize], PAGE_SIZE)
+            .unwrap();
+        conn.sync(false).unwrap();
+        let (freed_offset, _, freed_capacity) = conn.read_directory_entry(2).unwrap();
+        let superseded_directory = conn.root.lock().unwrap().directory_base.unwrap();
+        let superseded_capacity = (DIRECTORY_INITIAL_CAPACITY * DIRECTORY_ENTRY_SIZE) as u32;
+
+        conn.set_len(2 * PAGE_SIZE).unwrap();
+        conn.sync(false).unwrap();
+        assert!(conn.read_directory_entry(2).is_none());
+
+        // Regrow back to 3 pages -- the new page 2 should come from the free
+        // list instead of bump-allocating past the arena's current
+        // high-water mark. The truncating commit also relocated the page
+        // directory and freed the superseded one before page 2's old slot,
+        // so first fit hands out the old directory's slot, and page 2's old
+        // slot stays on the free list.
+        conn.write_all_at(&[9u8; PAGE_SIZE as usize], 2 * PAGE_SIZE)
+            .unwrap();
+        conn.sync(false).unwrap();
+
+        let (reused_offset, _, reused_capacity) = conn.read_directory_entry(2).unwrap();
+        assert_eq!(reused_offset, superseded_directory);
+        assert_eq!(reused_capacity, superseded_capacity);
+        assert!(conn
+            .blob_allocator
+            .lock()
+            .unwrap()
+            .free_list
+            .as_ref()
+            .unwrap()
+            .contains(&(freed_offset, freed_capacity)));
+
+        let mut page = vec![0u8; PAGE_SIZE as usize];
+        conn.read_exact_at(&mut page, 2 * PAGE_SIZE).unwrap();
+        assert_eq!(page, vec![9u8; PAGE_SIZE as usize]);
+    }
+
+    #[test]
+    fn directory_entry_round_trips_through_its_byte_encoding() {
+        let cases = [
+            (0u64, 0u32, 0u32),
+            (INITIAL_TAIL_OFFSET, 123, 256),
+            (u64::MAX, u32::MAX, u32::MAX),
+        ];
+        for &(offset, stored_len, capacity) in &cases {
+            let encoded = encode_directory_entry(offset, stored_len, capacity);
+            assert_eq!(encoded.len(), DIRECTORY_ENTRY_SIZE as usize);
+            assert_eq!(
+                decode_directory_entry(&encoded),
+                if stored_len == 0 && capacity == 0 {
+                    None
+                } else {
+                    Some((offset, stored_len, capacity))
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn compressed_page_round_trips_to_the_original_bytes() {
+        let page = vec![7u8; PAGE_SIZE as usize];
+        let compressed = compress_page(&page);
+        assert_eq!(decompress_page(&compressed), page);
+    }
+
+    #[test]
+    fn decompressing_garbage_falls_back_to_a_zero_page_instead_of_panicking() {
+        let garbage = vec![0xffu8; 16];
+        assert_eq!(decompress_page(&garbage), vec![0u8; PAGE_SIZE as usize]);
+    }
+}